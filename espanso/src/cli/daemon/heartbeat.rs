@@ -0,0 +1,217 @@
+/*
+ * This file is part of espanso.
+ *
+ * Copyright (C) 2019-2021 Federico Terzi
+ *
+ * espanso is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * espanso is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with espanso.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+  path::{Path, PathBuf},
+  time::{Duration, Instant, SystemTime},
+};
+
+use crossbeam::channel::{unbounded, Receiver};
+use espanso_ipc::IPCClient;
+use log::{error, warn};
+
+use crate::ipc::{create_ipc_client_to_worker, IPCEvent};
+
+pub const DEFAULT_PERIOD: Duration = Duration::from_secs(5);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+pub const DEFAULT_MAX_MISSED: u32 = 3;
+
+// The daemon expects the worker to touch this file inside the runtime dir
+// every time it receives an `IPCEvent::Ping`, acting as its `Pong`. Like
+// the "worker.version" file, this write belongs to the worker process and
+// is not part of this daemon-side code.
+const HEARTBEAT_FILE_NAME: &str = "worker.heartbeat";
+const DIAGNOSTIC_FILE_NAME: &str = "worker.hang-diagnostic.log";
+
+/// Tracks consecutive missed heartbeats in isolation from the IPC/filesystem
+/// side of the check, so the "how many misses before we call it a hang"
+/// decision can be unit-tested the same way `CrashSupervisor` is.
+struct MissedHeartbeatTracker {
+  missed_count: u32,
+  max_missed: u32,
+}
+
+impl MissedHeartbeatTracker {
+  fn new(max_missed: u32) -> Self {
+    Self {
+      missed_count: 0,
+      max_missed,
+    }
+  }
+
+  /// Records whether the latest ping got a pong within its timeout,
+  /// returning `true` once `max_missed` consecutive misses have
+  /// accumulated (and resetting the counter so the next call starts
+  /// counting fresh).
+  fn record(&mut self, pong_received: bool) -> bool {
+    if pong_received {
+      self.missed_count = 0;
+      return false;
+    }
+
+    self.missed_count += 1;
+    if self.missed_count >= self.max_missed {
+      self.missed_count = 0;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn missed_count(&self) -> u32 {
+    self.missed_count
+  }
+}
+
+/// Spawns a background thread that periodically pings the worker process
+/// over IPC and expects it to touch the heartbeat file within `timeout`.
+/// After `max_missed` consecutive misses, a notification is pushed on the
+/// returned channel so the caller can treat the worker as hung (as opposed
+/// to crashed, which is already detected by `worker_monitor`) and restart
+/// it.
+pub fn spawn(
+  runtime_dir: PathBuf,
+  period: Duration,
+  timeout: Duration,
+  max_missed: u32,
+) -> Receiver<()> {
+  let (hang_notify, hang_signal) = unbounded();
+
+  std::thread::Builder::new()
+    .name("worker-heartbeat".to_string())
+    .spawn(move || {
+      let mut tracker = MissedHeartbeatTracker::new(max_missed);
+
+      loop {
+        std::thread::sleep(period);
+
+        // Sample the heartbeat file *before* sending the ping, otherwise a
+        // worker that responds fast enough can touch the file between our
+        // `send` and the baseline read, making a healthy reply look like a
+        // missed heartbeat.
+        let heartbeat_file = runtime_dir.join(HEARTBEAT_FILE_NAME);
+        let baseline = last_modified(&heartbeat_file);
+
+        match create_ipc_client_to_worker(&runtime_dir) {
+          Ok(worker_ipc) => {
+            if let Err(err) = worker_ipc.send(IPCEvent::Ping) {
+              warn!("unable to send heartbeat ping to worker process: {}", err);
+            }
+          }
+          Err(err) => warn!(
+            "unable to create IPC client for the heartbeat check: {}",
+            err
+          ),
+        }
+
+        let pong_received = wait_for_pong(&heartbeat_file, baseline, timeout);
+        if !pong_received {
+          warn!(
+            "missed {}/{} heartbeats from the worker process",
+            tracker.missed_count() + 1,
+            max_missed
+          );
+        }
+
+        if tracker.record(pong_received) {
+          error!(
+            "worker process appears to be hung after {} missed heartbeats",
+            max_missed
+          );
+          capture_diagnostic(&runtime_dir, max_missed);
+
+          if hang_notify.send(()).is_err() {
+            break;
+          }
+        }
+      }
+    })
+    .expect("unable to spawn worker heartbeat thread");
+
+  hang_signal
+}
+
+fn wait_for_pong(heartbeat_file: &Path, baseline: Option<SystemTime>, timeout: Duration) -> bool {
+  let now = Instant::now();
+  while now.elapsed() < timeout {
+    if last_modified(heartbeat_file) != baseline {
+      return true;
+    }
+
+    std::thread::sleep(Duration::from_millis(100));
+  }
+
+  false
+}
+
+fn last_modified(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+// Best-effort diagnostic dump so a hang can be investigated after the fact;
+// failures to write it are logged but never block the restart.
+fn capture_diagnostic(runtime_dir: &Path, missed_count: u32) {
+  let diagnostic_file = runtime_dir.join(DIAGNOSTIC_FILE_NAME);
+  let contents = format!(
+    "worker process missed {} heartbeats, restarting at {:?}\n",
+    missed_count,
+    SystemTime::now(),
+  );
+
+  if let Err(err) = std::fs::write(&diagnostic_file, contents) {
+    warn!(
+      "unable to write hang diagnostic to {:?}: {}",
+      diagnostic_file, err
+    );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resets_on_a_received_pong() {
+    let mut tracker = MissedHeartbeatTracker::new(3);
+    assert!(!tracker.record(false));
+    assert!(!tracker.record(false));
+    assert!(!tracker.record(true));
+    assert_eq!(tracker.missed_count(), 0);
+  }
+
+  #[test]
+  fn flags_a_hang_after_max_missed_consecutive_misses() {
+    let mut tracker = MissedHeartbeatTracker::new(3);
+    assert!(!tracker.record(false));
+    assert!(!tracker.record(false));
+    assert!(tracker.record(false));
+    // The counter resets once a hang is flagged, so the next miss starts
+    // counting fresh rather than flagging again immediately.
+    assert_eq!(tracker.missed_count(), 0);
+  }
+
+  #[test]
+  fn keeps_flagging_on_every_subsequent_run_of_misses() {
+    let mut tracker = MissedHeartbeatTracker::new(2);
+    assert!(!tracker.record(false));
+    assert!(tracker.record(false));
+    assert!(!tracker.record(false));
+    assert!(tracker.record(false));
+  }
+}
@@ -17,30 +17,55 @@
  * along with espanso.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::{path::Path, process::Command, time::Instant};
+use std::{fs::read_to_string, path::Path, process::Command, time::Instant};
 
+use clap::Arg;
 use crossbeam::{
-  channel::{unbounded, Sender},
+  channel::{after, never, unbounded, Receiver, Sender},
   select,
 };
-use espanso_ipc::IPCClient;
 use espanso_path::Paths;
 use log::{error, info, warn};
 
 use crate::{
   ipc::{create_ipc_client_to_worker, IPCEvent},
-  lock::{acquire_daemon_lock, acquire_worker_lock},
+  lock::acquire_daemon_lock,
 };
 
 use super::{CliModule, CliModuleArgs};
 
+mod heartbeat;
 mod ipc;
+mod signals;
+mod supervisor;
+mod termination;
+mod watcher;
+mod worker_monitor;
+
+use supervisor::{CrashSupervisor, Decision};
+use termination::TerminationConfig;
+use watcher::OnConfigChangeMode;
+use worker_monitor::WorkerPid;
 
 pub enum ExitCode {
   Success = 0,
   ExitCodeUnwrapError = 100,
+  WorkerVersionMismatch = 101,
+  WorkerTerminationTimedOut = 102,
 }
 
+// Name of the env var used to tell the spawned worker process which version
+// the daemon expects it to be. The daemon expects the worker to write its own
+// version back into the "worker.version" file inside the runtime dir (mirroring the lock-file
+// handshake already used by `acquire_worker_lock`), so the daemon can detect
+// a stale worker binary left over from an in-place upgrade. The worker-side
+// write hasn't landed yet, so the handshake is opt-in (see
+// "strict-worker-handshake" below) until it does, rather than failing every
+// startup.
+const DAEMON_VERSION_ENV_VAR: &str = "ESPANSO_DAEMON_VERSION";
+const WORKER_VERSION_FILE_NAME: &str = "worker.version";
+const WORKER_VERSION_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
 pub fn new() -> CliModule {
   #[allow(clippy::needless_update)]
   CliModule {
@@ -50,6 +75,33 @@ pub fn new() -> CliModule {
     log_mode: super::LogMode::CleanAndAppend,
     subcommand: "daemon".to_string(),
     entry: daemon_main,
+    args: vec![
+      Arg::with_name("stop-signal")
+        .long("stop-signal")
+        .takes_value(true)
+        .help("signal sent to the worker process when escalating a graceful shutdown (TERM, INT or KILL, defaults to TERM)"),
+      Arg::with_name("stop-timeout")
+        .long("stop-timeout")
+        .takes_value(true)
+        .help("seconds to wait at each stage (IPC exit, stop-signal, SIGKILL) before escalating worker termination further, defaults to 3"),
+      Arg::with_name("on-config-change")
+        .long("on-config-change")
+        .takes_value(true)
+        .possible_values(&["reload", "restart", "do-nothing"])
+        .help("what to do with the worker process when a config file changes, defaults to reload"),
+      Arg::with_name("auto-restart")
+        .long("auto-restart")
+        .takes_value(false)
+        .help("automatically restart the worker process (with exponential backoff) instead of exiting when it crashes"),
+      Arg::with_name("strict-worker-handshake")
+        .long("strict-worker-handshake")
+        .takes_value(false)
+        .help("refuse to proceed unless the worker process confirms its version over the startup handshake (requires a worker build that reports its version; disabled by default)"),
+      Arg::with_name("enable-heartbeat-check")
+        .long("enable-heartbeat-check")
+        .takes_value(false)
+        .help("restart the worker process if it misses heartbeat pings (requires a worker build that replies to them; disabled by default)"),
+    ],
     ..Default::default()
   }
 }
@@ -71,37 +123,189 @@ fn daemon_main(args: CliModuleArgs) -> i32 {
   info!("espanso version: {}", VERSION);
   // TODO: print os system and version? (with os_info crate)
 
+  let termination_config = TerminationConfig::parse(
+    args.cli_args.value_of("stop-signal"),
+    args.cli_args.value_of("stop-timeout"),
+  );
+
   let worker_ipc = create_ipc_client_to_worker(&paths.runtime)
     .expect("unable to create IPC client to worker process");
 
-  terminate_worker_if_already_running(&paths.runtime, worker_ipc);
+  // No worker has been spawned by this daemon process yet, so `worker_pid`
+  // can't know the pid of a worker left running by a previous daemon
+  // instance; only the IPC-exit stage of the escalation below can reach it.
+  let worker_pid = WorkerPid::new();
+  if let Err(err) = termination::terminate_worker_if_already_running(
+    &paths.runtime,
+    worker_ipc,
+    &termination_config,
+    &worker_pid,
+  ) {
+    error!("{}", err);
+    return ExitCode::WorkerTerminationTimedOut as i32;
+  }
 
   let (exit_notify, exit_signal) = unbounded::<i32>();
 
-  // TODO: register signals to terminate the worker if the daemon terminates
+  signals::register(exit_notify.clone());
+
+  spawn_worker(&paths, exit_notify.clone(), &worker_pid);
 
-  spawn_worker(&paths, exit_notify.clone());
+  // The handshake relies on the worker writing "worker.version" on startup,
+  // which no shipped worker build does yet, so it's opt-in until that lands
+  // to avoid every daemon start timing out and killing the worker it just
+  // spawned.
+  if args.cli_args.is_present("strict-worker-handshake") {
+    match verify_worker_version(&paths.runtime) {
+      Ok(worker_version) => {
+        info!("worker process reported version: {}", worker_version);
+      }
+      Err(VersionMismatch::Stale(worker_version)) => {
+        error!(
+          "worker process version ({}) does not match daemon version ({}), re-executing daemon from the current binary",
+          worker_version, VERSION
+        );
+        kill_unverified_worker(&paths.runtime, &termination_config, &worker_pid);
+        return re_exec_daemon();
+      }
+      Err(VersionMismatch::Timeout) => {
+        error!("worker process did not report its version in time, refusing to proceed");
+        kill_unverified_worker(&paths.runtime, &termination_config, &worker_pid);
+        return ExitCode::WorkerVersionMismatch as i32;
+      }
+    }
+  }
 
-  ipc::initialize_and_spawn(&paths.runtime, exit_notify)
+  ipc::initialize_and_spawn(&paths.runtime, exit_notify.clone())
     .expect("unable to initialize ipc server for daemon");
 
-  // TODO: start file watcher thread
+  let on_config_change_mode =
+    OnConfigChangeMode::parse(args.cli_args.value_of("on-config-change"));
+  info!("config change policy: {:?}", on_config_change_mode);
+  let config_change_signal = watcher::spawn(vec![paths.config.clone(), paths.packages.clone()]);
+
+  // Like the version handshake above, this relies on the worker touching
+  // "worker.heartbeat" on every ping, which no shipped worker build does
+  // yet. Left unconditional, every ping would be a guaranteed miss and
+  // force-restart an otherwise healthy worker every `DEFAULT_MAX_MISSED`
+  // pings, so the check is opt-in until the worker-side reply lands;
+  // `never()` keeps the arm dormant in the meantime.
+  let worker_hang_signal = if args.cli_args.is_present("enable-heartbeat-check") {
+    heartbeat::spawn(
+      paths.runtime.clone(),
+      heartbeat::DEFAULT_PERIOD,
+      heartbeat::DEFAULT_TIMEOUT,
+      heartbeat::DEFAULT_MAX_MISSED,
+    )
+  } else {
+    never()
+  };
+
+  // Opt-in supervision: instead of exiting as soon as the worker crashes,
+  // respawn it with exponential backoff, giving up (and exiting with the
+  // last worker code) only after a crash loop is detected.
+  let auto_restart_worker = args.cli_args.is_present("auto-restart");
+  let mut crash_supervisor = CrashSupervisor::new();
+  // Fires once a restart backoff elapses; `never()` keeps this arm dormant
+  // (rather than blocking the whole reactor in a `thread::sleep`) whenever
+  // no restart is pending, so the `exit_signal` arm chunk0-5 relies on for
+  // prompt shutdown keeps being serviced during the backoff.
+  let mut worker_restart_timer: Receiver<Instant> = never();
 
   let mut exit_code: i32 = ExitCode::Success as i32;
 
-  loop {
+  'main: loop {
     select! {
       recv(exit_signal) -> code => {
         match code {
+          Ok(code) if code == signals::SIGNAL_EXIT_CODE => {
+            info!("shutting down due to a termination signal, stopping the worker process...");
+            exit_code = ExitCode::Success as i32;
+            match create_ipc_client_to_worker(&paths.runtime) {
+              Ok(worker_ipc) => {
+                if let Err(err) = termination::terminate_worker_if_already_running(&paths.runtime, worker_ipc, &termination_config, &worker_pid) {
+                  error!("{}", err);
+                  exit_code = ExitCode::WorkerTerminationTimedOut as i32;
+                }
+              },
+              Err(err) => {
+                error!("unable to create IPC client to worker process: {}", err);
+                exit_code = ExitCode::WorkerTerminationTimedOut as i32;
+              },
+            }
+            break 'main;
+          },
+          Ok(code) if auto_restart_worker => {
+            match crash_supervisor.on_worker_crash() {
+              Decision::Restart(backoff) => {
+                warn!("worker process exited with code {}, restarting in {:?}...", code, backoff);
+                worker_restart_timer = after(backoff);
+              },
+              Decision::GiveUp => {
+                error!(
+                  "worker process crashed {} times within the crash-loop window, giving up",
+                  crash_supervisor.restart_count()
+                );
+                exit_code = code;
+                break 'main;
+              },
+            }
+          },
           Ok(code) => {
-            exit_code = code
+            exit_code = code;
+            break 'main;
           },
           Err(err) => {
             error!("received error when unwrapping exit_code: {}", err);
             exit_code = ExitCode::ExitCodeUnwrapError as i32;
+            break 'main;
+          },
+        }
+      },
+      recv(config_change_signal) -> _ => {
+        info!("detected a configuration change, applying on-change policy: {:?}", on_config_change_mode);
+        match on_config_change_mode {
+          OnConfigChangeMode::Reload => {
+            match create_ipc_client_to_worker(&paths.runtime) {
+              Ok(worker_ipc) => {
+                if let Err(err) = worker_ipc.send(IPCEvent::Reload) {
+                  error!("unable to send reload signal to worker process: {}", err);
+                }
+              },
+              Err(err) => error!("unable to create IPC client to worker process: {}", err),
+            }
+          },
+          OnConfigChangeMode::Restart => {
+            match create_ipc_client_to_worker(&paths.runtime) {
+              Ok(worker_ipc) => {
+                if let Err(err) = termination::terminate_worker_if_already_running(&paths.runtime, worker_ipc, &termination_config, &worker_pid) {
+                  error!("{}", err);
+                } else {
+                  spawn_worker(&paths, exit_notify.clone(), &worker_pid);
+                }
+              },
+              Err(err) => error!("unable to create IPC client to worker process: {}", err),
+            }
           },
+          OnConfigChangeMode::DoNothing => {},
         }
-        break;
+      },
+      recv(worker_hang_signal) -> _ => {
+        warn!("restarting the worker process after repeated missed heartbeats");
+        match create_ipc_client_to_worker(&paths.runtime) {
+          Ok(worker_ipc) => {
+            if let Err(err) = termination::terminate_worker_if_already_running(&paths.runtime, worker_ipc, &termination_config, &worker_pid) {
+              error!("{}", err);
+            } else {
+              spawn_worker(&paths, exit_notify.clone(), &worker_pid);
+            }
+          },
+          Err(err) => error!("unable to create IPC client to worker process: {}", err),
+        }
+      },
+      recv(worker_restart_timer) -> _ => {
+        worker_restart_timer = never();
+        spawn_worker(&paths, exit_notify.clone(), &worker_pid);
       },
     }
   }
@@ -109,36 +313,97 @@ fn daemon_main(args: CliModuleArgs) -> i32 {
   exit_code
 }
 
-fn terminate_worker_if_already_running(runtime_dir: &Path, worker_ipc: impl IPCClient<IPCEvent>) {
-  let lock_file = acquire_worker_lock(&runtime_dir);
-  if lock_file.is_some() {
-    return;
-  }
+enum VersionMismatch {
+  // Carries the (different) version reported by the worker
+  Stale(String),
+  Timeout,
+}
 
-  warn!("a worker process is already running, sending termination signal...");
-  if let Err(err) = worker_ipc.send(IPCEvent::Exit) {
-    error!(
-      "unable to send termination signal to worker process: {}",
-      err
-    );
-  }
+// After an in-place upgrade, the daemon binary on disk might be newer than
+// the one that is still running, which means a freshly spawned worker could
+// be speaking a different protocol version than the daemon expects. Block
+// briefly waiting for the worker to report its own `CARGO_PKG_VERSION` and
+// bail out (instead of silently attaching) if it doesn't match.
+fn verify_worker_version(runtime_dir: &Path) -> Result<String, VersionMismatch> {
+  let version_file = runtime_dir.join(WORKER_VERSION_FILE_NAME);
 
   let now = Instant::now();
-  while now.elapsed() < std::time::Duration::from_secs(3) {
-    let lock_file = acquire_worker_lock(runtime_dir);
-    if lock_file.is_some() {
-      return;
+  while now.elapsed() < WORKER_VERSION_HANDSHAKE_TIMEOUT {
+    if let Ok(worker_version) = read_to_string(&version_file) {
+      let worker_version = worker_version.trim().to_string();
+      return if worker_version == VERSION {
+        Ok(worker_version)
+      } else {
+        Err(VersionMismatch::Stale(worker_version))
+      };
     }
 
-    std::thread::sleep(std::time::Duration::from_millis(200));
+    std::thread::sleep(std::time::Duration::from_millis(100));
   }
 
-  panic!(
-    "could not terminate worker process, please kill it manually, otherwise espanso won't start"
-  )
+  Err(VersionMismatch::Timeout)
+}
+
+// A worker that fails the version handshake must never be left attached:
+// tear it down through the same escalating IPC->stop-signal->SIGKILL path
+// used everywhere else, rather than leaving the freshly spawned (and now
+// unsupervised) process running.
+fn kill_unverified_worker(
+  runtime_dir: &Path,
+  termination_config: &TerminationConfig,
+  worker_pid: &WorkerPid,
+) {
+  match create_ipc_client_to_worker(runtime_dir) {
+    Ok(worker_ipc) => {
+      if let Err(err) = termination::terminate_worker_if_already_running(
+        runtime_dir,
+        worker_ipc,
+        termination_config,
+        worker_pid,
+      ) {
+        error!("unable to terminate the unverified worker process: {}", err);
+      }
+    }
+    Err(err) => error!(
+      "unable to create IPC client to terminate the unverified worker process: {}",
+      err
+    ),
+  }
+}
+
+// Re-executes the daemon process in place, picking up whatever binary is
+// currently on disk. This is what lets the daemon recover on its own after
+// an in-place upgrade left a version-mismatched worker behind.
+#[cfg(unix)]
+fn re_exec_daemon() -> i32 {
+  use std::os::unix::process::CommandExt;
+
+  let espanso_exe_path =
+    std::env::current_exe().expect("unable to obtain espanso executable location");
+  let err = Command::new(espanso_exe_path)
+    .args(std::env::args().skip(1))
+    .exec();
+  error!("failed to re-exec daemon process: {}", err);
+  ExitCode::WorkerVersionMismatch as i32
 }
 
-fn spawn_worker(paths: &Paths, exit_notify: Sender<i32>) {
+#[cfg(not(unix))]
+fn re_exec_daemon() -> i32 {
+  let espanso_exe_path =
+    std::env::current_exe().expect("unable to obtain espanso executable location");
+  match Command::new(espanso_exe_path)
+    .args(std::env::args().skip(1))
+    .spawn()
+  {
+    Ok(_) => ExitCode::Success as i32,
+    Err(err) => {
+      error!("failed to re-exec daemon process: {}", err);
+      ExitCode::WorkerVersionMismatch as i32
+    }
+  }
+}
+
+fn spawn_worker(paths: &Paths, exit_notify: Sender<i32>, worker_pid: &WorkerPid) {
   info!("spawning the worker process...");
 
   let espanso_exe_path =
@@ -158,6 +423,7 @@ fn spawn_worker(paths: &Paths, exit_notify: Sender<i32>) {
     "ESPANSO_RUNTIME_DIR",
     paths.runtime.to_string_lossy().to_string(),
   );
+  command.env(DAEMON_VERSION_ENV_VAR, VERSION);
 
   // TODO: investigate if this is needed here, especially when invoking a form
   // // On windows, we need to spawn the process as "Detached"
@@ -167,27 +433,10 @@ fn spawn_worker(paths: &Paths, exit_notify: Sender<i32>) {
   //   //command.creation_flags(0x08000008); // CREATE_NO_WINDOW + DETACHED_PROCESS
   // }
 
-  let mut child = command.spawn().expect("unable to spawn worker process");
-
-  // Create a monitor thread that will exit with the same non-zero code if
-  // the worker thread exits
-  std::thread::Builder::new()
-    .name("worker-status-monitor".to_string())
-    .spawn(move || {
-      let result = child.wait();
-      if let Ok(status) = result {
-        if let Some(code) = status.code() {
-          if code != 0 {
-            error!(
-              "worker process exited with non-zero code: {}, exiting",
-              code
-            );
-            exit_notify
-              .send(code)
-              .expect("unable to forward worker exit code");
-          }
-        }
-      }
-    })
-    .expect("Unable to spawn worker monitor thread");
+  let child = command.spawn().expect("unable to spawn worker process");
+
+  // Monitor the worker process and forward its exit code through
+  // `exit_notify` if it terminates abnormally; also records its pid so it
+  // can be signaled directly later on.
+  worker_monitor::spawn(child, exit_notify, worker_pid.clone());
 }
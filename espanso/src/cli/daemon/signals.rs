@@ -0,0 +1,113 @@
+/*
+ * This file is part of espanso.
+ *
+ * Copyright (C) 2019-2021 Federico Terzi
+ *
+ * espanso is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * espanso is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with espanso.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crossbeam::channel::Sender;
+use log::{error, warn};
+
+// On Unix, worker exit codes forwarded through `exit_notify` are always in
+// the 0..=255 range (they come straight from `ExitStatus::code()`), so a
+// negative sentinel is unambiguous there and lets the main `select!` loop
+// tell a signal-triggered shutdown apart from a worker crash. On Windows,
+// `ExitStatus::code()` is the full 32-bit exit code, so a worker exiting
+// with code -1 is treated as a termination signal.
+pub const SIGNAL_EXIT_CODE: i32 = -1;
+
+// Set right before the daemon itself asks the worker to exit (IPC exit,
+// stop-signal or SIGKILL), so `handle_sigchld` can tell that kind of exit
+// apart from the worker dying on its own. A plain `AtomicBool` keeps this
+// safe to touch from the signal handler, which can only use
+// async-signal-safe operations.
+static WORKER_SHUTDOWN_EXPECTED: AtomicBool = AtomicBool::new(false);
+
+/// Marks the next SIGCHLD as an expected worker shutdown rather than a
+/// crash. Called by `termination` right before it asks the worker to exit.
+pub fn mark_worker_shutdown_expected() {
+  WORKER_SHUTDOWN_EXPECTED.store(true, Ordering::SeqCst);
+}
+
+/// Registers cross-platform termination handlers (SIGINT/SIGTERM on Unix,
+/// CTRL_C/CTRL_BREAK/CTRL_CLOSE on Windows) that push `SIGNAL_EXIT_CODE`
+/// into `exit_notify`, waking up the daemon's main `select!` loop so it can
+/// run the regular shutdown path (which tears down the worker process)
+/// instead of just dying and leaving the worker orphaned.
+pub fn register(exit_notify: Sender<i32>) {
+  if let Err(err) = ctrlc::set_handler(move || {
+    warn!("received a termination signal, shutting down...");
+    if exit_notify.send(SIGNAL_EXIT_CODE).is_err() {
+      error!("unable to forward the termination signal to the main loop");
+    }
+  }) {
+    error!("unable to register termination signal handlers: {}", err);
+  }
+
+  #[cfg(unix)]
+  unix::install_sigchld_handler();
+}
+
+#[cfg(unix)]
+mod unix {
+  use std::sync::atomic::Ordering;
+
+  use log::warn;
+
+  use super::WORKER_SHUTDOWN_EXPECTED;
+
+  // The worker's exit is already detected (and reaped) by the pidfd/wait
+  // based monitor in `worker_monitor`, so the daemon doesn't need to reap
+  // it here. We still install an explicit handler instead of leaving the
+  // default disposition, so it can log whether the exit was one the daemon
+  // itself asked for or an unexpected crash.
+  pub fn install_sigchld_handler() {
+    unsafe {
+      let mut action: libc::sigaction = std::mem::zeroed();
+      action.sa_sigaction = handle_sigchld as usize;
+      libc::sigemptyset(&mut action.sa_mask);
+      action.sa_flags = libc::SA_RESTART;
+
+      if libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut()) != 0 {
+        warn!(
+          "unable to install SIGCHLD handler: {}",
+          std::io::Error::last_os_error()
+        );
+      }
+    }
+  }
+
+  // Only async-signal-safe operations are allowed here: an atomic swap and
+  // a raw `write(2)` to stderr, rather than the `log` macros used
+  // everywhere else in the daemon (which can allocate or lock).
+  extern "C" fn handle_sigchld(_signal: libc::c_int) {
+    let expected = WORKER_SHUTDOWN_EXPECTED.swap(false, Ordering::SeqCst);
+    let message: &[u8] = if expected {
+      b"espanso: worker process exited as part of an expected shutdown\n"
+    } else {
+      b"espanso: worker process exited unexpectedly, possibly a crash\n"
+    };
+
+    unsafe {
+      libc::write(
+        libc::STDERR_FILENO,
+        message.as_ptr() as *const libc::c_void,
+        message.len(),
+      );
+    }
+  }
+}
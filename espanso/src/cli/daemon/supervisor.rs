@@ -0,0 +1,172 @@
+/*
+ * This file is part of espanso.
+ *
+ * Copyright (C) 2019-2021 Federico Terzi
+ *
+ * espanso is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * espanso is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with espanso.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const CRASH_LOOP_WINDOW: Duration = Duration::from_secs(60);
+const MAX_RESTARTS_IN_WINDOW: u32 = 5;
+
+pub enum Decision {
+  // The worker should be respawned after waiting the given backoff
+  Restart(Duration),
+  // Too many crashes happened within the crash-loop window, give up
+  GiveUp,
+}
+
+/// Tracks worker crashes so that a single transient fault (e.g. a
+/// detached-process quirk on Windows) can self-heal via an automatic
+/// restart, while a genuine crash loop still brings the daemon down
+/// instead of spinning forever.
+pub struct CrashSupervisor {
+  restarts: Vec<Instant>,
+  initial_backoff: Duration,
+  max_backoff: Duration,
+  window: Duration,
+  max_restarts: u32,
+}
+
+impl CrashSupervisor {
+  pub fn new() -> Self {
+    Self::with_config(
+      INITIAL_BACKOFF,
+      MAX_BACKOFF,
+      CRASH_LOOP_WINDOW,
+      MAX_RESTARTS_IN_WINDOW,
+    )
+  }
+
+  pub fn with_config(
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    window: Duration,
+    max_restarts: u32,
+  ) -> Self {
+    Self {
+      restarts: Vec::new(),
+      initial_backoff,
+      max_backoff,
+      window,
+      max_restarts,
+    }
+  }
+
+  /// Records a worker crash and decides whether it should be restarted.
+  pub fn on_worker_crash(&mut self) -> Decision {
+    let now = Instant::now();
+    self
+      .restarts
+      .retain(|restart| now.duration_since(*restart) < self.window);
+    self.restarts.push(now);
+
+    let crashes_in_window = self.restarts.len() as u32;
+    if crashes_in_window > self.max_restarts {
+      return Decision::GiveUp;
+    }
+
+    // Backoff is derived purely from how many crashes are still inside the
+    // window, so it naturally falls back to `initial_backoff` once the
+    // worker has been stable long enough for older crashes to expire out
+    // of the window, rather than escalating forever from a counter that's
+    // never reset.
+    let backoff = self.initial_backoff.saturating_mul(1 << (crashes_in_window - 1));
+    Decision::Restart(backoff.min(self.max_backoff))
+  }
+
+  pub fn restart_count(&self) -> usize {
+    self.restarts.len()
+  }
+}
+
+impl Default for CrashSupervisor {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn backoff_escalates_on_repeated_crashes() {
+    let mut supervisor =
+      CrashSupervisor::with_config(Duration::from_millis(10), Duration::from_secs(10), Duration::from_secs(60), 5);
+
+    let first = match supervisor.on_worker_crash() {
+      Decision::Restart(backoff) => backoff,
+      Decision::GiveUp => panic!("expected a restart decision"),
+    };
+    let second = match supervisor.on_worker_crash() {
+      Decision::Restart(backoff) => backoff,
+      Decision::GiveUp => panic!("expected a restart decision"),
+    };
+
+    assert!(second > first);
+  }
+
+  #[test]
+  fn backoff_is_capped_at_max_backoff() {
+    let mut supervisor =
+      CrashSupervisor::with_config(Duration::from_secs(10), Duration::from_secs(15), Duration::from_secs(60), 5);
+
+    supervisor.on_worker_crash();
+    let backoff = match supervisor.on_worker_crash() {
+      Decision::Restart(backoff) => backoff,
+      Decision::GiveUp => panic!("expected a restart decision"),
+    };
+
+    assert_eq!(backoff, Duration::from_secs(15));
+  }
+
+  #[test]
+  fn gives_up_after_max_restarts_in_window() {
+    let mut supervisor =
+      CrashSupervisor::with_config(Duration::from_millis(1), Duration::from_millis(10), Duration::from_secs(60), 2);
+
+    assert!(matches!(supervisor.on_worker_crash(), Decision::Restart(_)));
+    assert!(matches!(supervisor.on_worker_crash(), Decision::Restart(_)));
+    assert!(matches!(supervisor.on_worker_crash(), Decision::GiveUp));
+  }
+
+  #[test]
+  fn backoff_resets_once_old_crashes_fall_out_of_the_window() {
+    let mut supervisor = CrashSupervisor::with_config(
+      Duration::from_millis(5),
+      Duration::from_secs(10),
+      Duration::from_millis(20),
+      5,
+    );
+
+    let first = match supervisor.on_worker_crash() {
+      Decision::Restart(backoff) => backoff,
+      Decision::GiveUp => panic!("expected a restart decision"),
+    };
+
+    std::thread::sleep(Duration::from_millis(40));
+
+    let after_window = match supervisor.on_worker_crash() {
+      Decision::Restart(backoff) => backoff,
+      Decision::GiveUp => panic!("expected a restart decision"),
+    };
+
+    assert_eq!(first, after_window);
+  }
+}
@@ -0,0 +1,268 @@
+/*
+ * This file is part of espanso.
+ *
+ * Copyright (C) 2019-2021 Federico Terzi
+ *
+ * espanso is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * espanso is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with espanso.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+  fmt,
+  path::Path,
+  time::{Duration, Instant},
+};
+
+use espanso_ipc::IPCClient;
+use log::{error, warn};
+
+use crate::{ipc::IPCEvent, lock::acquire_worker_lock};
+
+use super::{signals, worker_monitor::WorkerPid};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopSignal {
+  Int,
+  Term,
+  Kill,
+}
+
+impl StopSignal {
+  pub fn parse(raw: Option<&str>) -> Self {
+    match raw.map(str::to_uppercase).as_deref() {
+      Some("SIGINT") | Some("INT") => Self::Int,
+      Some("SIGKILL") | Some("KILL") => Self::Kill,
+      Some("SIGTERM") | Some("TERM") => Self::Term,
+      Some(other) => {
+        warn!("unrecognized stop signal '{}', defaulting to SIGTERM", other);
+        Self::Term
+      }
+      None => Self::Term,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TerminationConfig {
+  pub stop_signal: StopSignal,
+  pub stop_timeout: Duration,
+}
+
+impl Default for TerminationConfig {
+  fn default() -> Self {
+    Self {
+      stop_signal: StopSignal::Term,
+      stop_timeout: Duration::from_secs(3),
+    }
+  }
+}
+
+impl TerminationConfig {
+  pub fn parse(stop_signal: Option<&str>, stop_timeout_secs: Option<&str>) -> Self {
+    let stop_timeout = stop_timeout_secs
+      .and_then(|raw| raw.parse::<u64>().ok())
+      .map(Duration::from_secs)
+      .unwrap_or_else(|| Self::default().stop_timeout);
+
+    Self {
+      stop_signal: StopSignal::parse(stop_signal),
+      stop_timeout,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct WorkerTerminationTimedOut;
+
+impl fmt::Display for WorkerTerminationTimedOut {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "could not terminate the worker process within the configured stop timeouts"
+    )
+  }
+}
+
+impl std::error::Error for WorkerTerminationTimedOut {}
+
+/// Gracefully terminates an already-running worker process, escalating from
+/// a polite IPC request all the way to a hard kill:
+///
+/// 1. Send `IPCEvent::Exit` and wait up to `stop_timeout`.
+/// 2. Send the configured `stop_signal` (SIGTERM by default) and wait up to
+///    `stop_timeout` again.
+/// 3. Send SIGKILL and wait up to `stop_timeout` one last time.
+///
+/// Succeeds as soon as the worker lock can be reacquired at any stage, and
+/// returns an error (rather than panicking) if the worker is still alive
+/// after exhausting every stage.
+///
+/// `worker_pid` is used to send the stop-signal/SIGKILL stages directly to
+/// the worker process; it only has a known pid once this daemon process has
+/// spawned a worker itself; a worker left over from a previous daemon
+/// instance (the very first call in `daemon_main`, before any worker has
+/// been spawned yet) can only be asked to exit over IPC.
+pub fn terminate_worker_if_already_running(
+  runtime_dir: &Path,
+  worker_ipc: impl IPCClient<IPCEvent>,
+  config: &TerminationConfig,
+  worker_pid: &WorkerPid,
+) -> Result<(), WorkerTerminationTimedOut> {
+  if acquire_worker_lock(runtime_dir).is_some() {
+    return Ok(());
+  }
+
+  // The daemon is about to ask the worker to exit itself, so its SIGCHLD
+  // should be logged as an expected shutdown rather than a crash.
+  signals::mark_worker_shutdown_expected();
+
+  warn!("a worker process is already running, sending termination signal...");
+  if let Err(err) = worker_ipc.send(IPCEvent::Exit) {
+    error!(
+      "unable to send termination signal to worker process: {}",
+      err
+    );
+  }
+
+  if wait_for_worker_lock(runtime_dir, config.stop_timeout) {
+    return Ok(());
+  }
+
+  warn!(
+    "worker process did not exit in time, escalating to stop signal {:?}",
+    config.stop_signal
+  );
+  send_signal_to_worker(worker_pid, config.stop_signal);
+
+  if wait_for_worker_lock(runtime_dir, config.stop_timeout) {
+    return Ok(());
+  }
+
+  warn!("worker process is still alive, escalating to a hard kill");
+  send_signal_to_worker(worker_pid, StopSignal::Kill);
+
+  if wait_for_worker_lock(runtime_dir, config.stop_timeout) {
+    return Ok(());
+  }
+
+  Err(WorkerTerminationTimedOut)
+}
+
+fn wait_for_worker_lock(runtime_dir: &Path, timeout: Duration) -> bool {
+  let now = Instant::now();
+  while now.elapsed() < timeout {
+    if acquire_worker_lock(runtime_dir).is_some() {
+      return true;
+    }
+
+    std::thread::sleep(Duration::from_millis(200));
+  }
+
+  false
+}
+
+#[cfg(unix)]
+fn send_signal_to_worker(worker_pid: &WorkerPid, signal: StopSignal) {
+  let raw_signal = match signal {
+    StopSignal::Int => libc::SIGINT,
+    StopSignal::Term => libc::SIGTERM,
+    StopSignal::Kill => libc::SIGKILL,
+  };
+
+  match worker_pid.get() {
+    Some(pid) => {
+      if unsafe { libc::kill(pid as libc::pid_t, raw_signal) } != 0 {
+        error!(
+          "unable to send signal {:?} to worker process {}: {}",
+          signal,
+          pid,
+          std::io::Error::last_os_error()
+        );
+      }
+    }
+    None => error!(
+      "unable to determine the worker process id, cannot send signal {:?}",
+      signal
+    ),
+  }
+}
+
+#[cfg(windows)]
+fn send_signal_to_worker(worker_pid: &WorkerPid, signal: StopSignal) {
+  // Windows has no POSIX signals: a "graceful" stop closes the main window,
+  // while SIGKILL-equivalent termination forcibly kills the process (`/F`);
+  // child processes of the worker are not targeted.
+  let force = matches!(signal, StopSignal::Kill);
+
+  match worker_pid.get() {
+    Some(pid) => {
+      let mut command = std::process::Command::new("taskkill");
+      command.args(&["/PID", &pid.to_string()]);
+      if force {
+        command.arg("/F");
+      }
+
+      if let Err(err) = command.status() {
+        error!(
+          "unable to send signal {:?} to worker process {}: {}",
+          signal, pid, err
+        );
+      }
+    }
+    None => error!(
+      "unable to determine the worker process id, cannot send signal {:?}",
+      signal
+    ),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_signal_names_case_insensitively() {
+    assert_eq!(StopSignal::parse(Some("int")), StopSignal::Int);
+    assert_eq!(StopSignal::parse(Some("SIGINT")), StopSignal::Int);
+    assert_eq!(StopSignal::parse(Some("kill")), StopSignal::Kill);
+    assert_eq!(StopSignal::parse(Some("SIGKILL")), StopSignal::Kill);
+    assert_eq!(StopSignal::parse(Some("term")), StopSignal::Term);
+    assert_eq!(StopSignal::parse(Some("SIGTERM")), StopSignal::Term);
+  }
+
+  #[test]
+  fn defaults_to_term_when_unset_or_unrecognized() {
+    assert_eq!(StopSignal::parse(None), StopSignal::Term);
+    assert_eq!(StopSignal::parse(Some("bogus")), StopSignal::Term);
+  }
+
+  #[test]
+  fn termination_config_falls_back_to_defaults() {
+    let config = TerminationConfig::parse(None, None);
+    assert_eq!(config.stop_signal, StopSignal::Term);
+    assert_eq!(config.stop_timeout, Duration::from_secs(3));
+  }
+
+  #[test]
+  fn termination_config_parses_valid_overrides() {
+    let config = TerminationConfig::parse(Some("KILL"), Some("10"));
+    assert_eq!(config.stop_signal, StopSignal::Kill);
+    assert_eq!(config.stop_timeout, Duration::from_secs(10));
+  }
+
+  #[test]
+  fn termination_config_falls_back_on_unparseable_timeout() {
+    let config = TerminationConfig::parse(None, Some("not-a-number"));
+    assert_eq!(config.stop_timeout, Duration::from_secs(3));
+  }
+}
@@ -0,0 +1,167 @@
+/*
+ * This file is part of espanso.
+ *
+ * Copyright (C) 2019-2021 Federico Terzi
+ *
+ * espanso is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * espanso is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with espanso.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{path::PathBuf, time::Duration};
+
+use crossbeam::channel::{unbounded, Receiver};
+use log::{error, warn};
+use notify::{RecursiveMode, Watcher};
+
+// A burst of filesystem events (a bulk editor save, a `git checkout`, a
+// package update) is coalesced into a single notification fired only after
+// this much quiescence, mirroring watchexec's debounce behavior.
+const DEBOUNCE_WINDOW: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConfigChangeMode {
+  // Tell the running worker to reload its configuration in-place
+  Reload,
+  // Terminate the running worker and spawn a new one
+  Restart,
+  // Ignore the change entirely
+  DoNothing,
+}
+
+impl OnConfigChangeMode {
+  pub fn parse(raw: Option<&str>) -> Self {
+    match raw {
+      Some("restart") => Self::Restart,
+      Some("do-nothing") | Some("noop") => Self::DoNothing,
+      // "reload" is also the historical, least surprising default
+      _ => Self::Reload,
+    }
+  }
+}
+
+/// Spawns a background thread that watches the given `paths` for changes,
+/// debouncing bursts of filesystem events into a single notification on the
+/// returned channel once `DEBOUNCE_WINDOW` has passed without further
+/// activity.
+pub fn spawn(paths: Vec<PathBuf>) -> Receiver<()> {
+  let (change_notify, change_signal) = unbounded();
+
+  std::thread::Builder::new()
+    .name("config-watcher".to_string())
+    .spawn(move || {
+      let (raw_notify, raw_signal) = unbounded();
+      let mut watcher = match notify::recommended_watcher(move |res: notify::Result<_>| {
+        if res.is_ok() {
+          // The change itself is irrelevant, we only care that *something*
+          // changed inside one of the watched directories.
+          let _ = raw_notify.send(());
+        }
+      }) {
+        Ok(watcher) => watcher,
+        Err(err) => {
+          error!("unable to initialize the config file watcher: {}", err);
+          return;
+        }
+      };
+
+      for path in &paths {
+        if let Err(err) = watcher.watch(path, RecursiveMode::Recursive) {
+          warn!("unable to watch path {:?} for changes: {}", path, err);
+        }
+      }
+
+      loop {
+        if !coalesce_debounce_burst(&raw_signal, DEBOUNCE_WINDOW) {
+          break;
+        }
+
+        if change_notify.send(()).is_err() {
+          break;
+        }
+      }
+    })
+    .expect("unable to spawn config watcher thread");
+
+  change_signal
+}
+
+/// Blocks until the first event of a (potential) burst arrives on
+/// `raw_signal`, then keeps resetting a `window`-long debounce timer for as
+/// long as more events keep arriving, so the whole burst collapses into a
+/// single `true`. Returns `false` once `raw_signal` is disconnected, with no
+/// burst to report.
+fn coalesce_debounce_burst(raw_signal: &Receiver<()>, window: Duration) -> bool {
+  if raw_signal.recv().is_err() {
+    return false;
+  }
+
+  while raw_signal.recv_timeout(window).is_ok() {}
+
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_known_modes() {
+    assert_eq!(OnConfigChangeMode::parse(Some("restart")), OnConfigChangeMode::Restart);
+    assert_eq!(OnConfigChangeMode::parse(Some("do-nothing")), OnConfigChangeMode::DoNothing);
+    assert_eq!(OnConfigChangeMode::parse(Some("noop")), OnConfigChangeMode::DoNothing);
+    assert_eq!(OnConfigChangeMode::parse(Some("reload")), OnConfigChangeMode::Reload);
+  }
+
+  #[test]
+  fn defaults_to_reload_when_unset_or_unrecognized() {
+    assert_eq!(OnConfigChangeMode::parse(None), OnConfigChangeMode::Reload);
+    assert_eq!(OnConfigChangeMode::parse(Some("bogus")), OnConfigChangeMode::Reload);
+  }
+
+  #[test]
+  fn coalesces_a_burst_of_events_into_one_signal() {
+    let (notify, signal) = unbounded();
+    let window = Duration::from_millis(20);
+
+    for _ in 0..5 {
+      notify.send(()).unwrap();
+      std::thread::sleep(Duration::from_millis(2));
+    }
+
+    assert!(coalesce_debounce_burst(&signal, window));
+    // The whole burst was consumed by the single coalesced call above.
+    assert!(signal.try_recv().is_err());
+  }
+
+  #[test]
+  fn treats_separate_bursts_as_separate_signals() {
+    let (notify, signal) = unbounded();
+    let window = Duration::from_millis(20);
+
+    notify.send(()).unwrap();
+    assert!(coalesce_debounce_burst(&signal, window));
+
+    std::thread::sleep(Duration::from_millis(40));
+
+    notify.send(()).unwrap();
+    assert!(coalesce_debounce_burst(&signal, window));
+  }
+
+  #[test]
+  fn returns_false_once_the_channel_is_disconnected() {
+    let (notify, signal) = unbounded::<()>();
+    drop(notify);
+
+    assert!(!coalesce_debounce_burst(&signal, Duration::from_millis(20)));
+  }
+}
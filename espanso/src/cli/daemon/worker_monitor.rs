@@ -0,0 +1,225 @@
+/*
+ * This file is part of espanso.
+ *
+ * Copyright (C) 2019-2021 Federico Terzi
+ *
+ * espanso is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * espanso is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with espanso.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::{
+  process::{Child, ExitStatus},
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+  },
+};
+
+use crossbeam::channel::Sender;
+use log::error;
+
+/// Shares the pid of the worker process this daemon most recently spawned
+/// with whoever needs to signal it directly (e.g. `termination`'s escalating
+/// stop-signal/SIGKILL path), without round-tripping through a pid file the
+/// worker would have to write itself.
+///
+/// A value of `0` stands for "no worker spawned by this daemon process yet",
+/// since `spawn` never hands out a `Child` with pid `0`.
+#[derive(Clone, Default)]
+pub struct WorkerPid(Arc<AtomicU32>);
+
+impl WorkerPid {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn get(&self) -> Option<u32> {
+    match self.0.load(Ordering::SeqCst) {
+      0 => None,
+      pid => Some(pid),
+    }
+  }
+
+  fn set(&self, pid: u32) {
+    self.0.store(pid, Ordering::SeqCst);
+  }
+}
+
+/// Spawns a background thread that waits for the given worker `child` to
+/// exit and forwards its exit code through `exit_notify` (only non-zero
+/// codes are forwarded, matching the daemon's "worker crashed" semantics).
+/// Also records `child`'s pid into `worker_pid` so it can be signaled
+/// directly later on.
+///
+/// The receiving end of `exit_notify` is an arm in the daemon's main
+/// `select!` loop, so from that loop's perspective a worker exit is just
+/// another event to react to. Observing the exit itself still needs a
+/// thread of its own: `Child::wait` (and the pidfd `poll` below) are
+/// blocking syscalls with no non-blocking or `select!`-compatible variant
+/// in std or `crossbeam`, and this crate doesn't depend on an async
+/// runtime that could await them directly. A dedicated thread that blocks
+/// on the syscall and forwards the result through the channel is the
+/// actual trade-off here, not a gap to close later.
+///
+/// On Linux kernels that support `pidfd_open` (5.3+), the child's exit is
+/// observed by polling a pidfd rather than calling the blocking `Child::wait`
+/// directly, so the monitor thread is parked in `poll()` instead of sitting
+/// inside a libc `waitpid` the whole time. On kernels without `pidfd_open`
+/// support (detected at runtime via `ENOSYS`) and on macOS/Windows, we fall
+/// back to the classic thread + `Child::wait()` approach.
+pub fn spawn(child: Child, exit_notify: Sender<i32>, worker_pid: WorkerPid) {
+  worker_pid.set(child.id());
+
+  #[cfg(target_os = "linux")]
+  {
+    if let Some(pidfd) = linux::try_open_pidfd(child.id()) {
+      linux::spawn_pidfd_reaper(child, pidfd, exit_notify);
+      return;
+    }
+  }
+
+  spawn_blocking_wait(child, exit_notify);
+}
+
+fn spawn_blocking_wait(mut child: Child, exit_notify: Sender<i32>) {
+  std::thread::Builder::new()
+    .name("worker-status-monitor".to_string())
+    .spawn(move || {
+      let result = child.wait();
+      forward_exit_code(result, &exit_notify);
+    })
+    .expect("Unable to spawn worker monitor thread");
+}
+
+fn forward_exit_code(result: std::io::Result<ExitStatus>, exit_notify: &Sender<i32>) {
+  if let Ok(status) = result {
+    if let Some(code) = status.code() {
+      if code != 0 {
+        error!(
+          "worker process exited with non-zero code: {}",
+          code
+        );
+        exit_notify
+          .send(code)
+          .expect("unable to forward worker exit code");
+      }
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+  use std::{
+    os::unix::io::RawFd,
+    process::Child,
+  };
+
+  use crossbeam::channel::Sender;
+  use log::warn;
+
+  use super::forward_exit_code;
+
+  // `pidfd_open` was introduced in Linux 5.3 and is exposed as a raw
+  // syscall on every architecture espanso targets, since libc crates did
+  // not wrap it until much later. Its number is architecture-specific, so
+  // we only know it for the architectures listed below; every other
+  // architecture (e.g. riscv64, powerpc64) falls back to blocking `wait()`.
+  #[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "x86",
+    target_arch = "arm"
+  ))]
+  const SYS_PIDFD_OPEN: libc::c_long = 434;
+
+  /// Attempts to open a pidfd for the given pid, returning `None` (and
+  /// logging the reason) if the running kernel doesn't support
+  /// `pidfd_open`, the call otherwise fails, or the syscall number isn't
+  /// known for the current architecture.
+  #[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "x86",
+    target_arch = "arm"
+  ))]
+  pub fn try_open_pidfd(pid: u32) -> Option<RawFd> {
+    let fd = unsafe { libc::syscall(SYS_PIDFD_OPEN, pid as libc::pid_t, 0) };
+    if fd < 0 {
+      let err = std::io::Error::last_os_error();
+      if err.raw_os_error() == Some(libc::ENOSYS) {
+        warn!("pidfd_open is not supported by this kernel, falling back to blocking wait()");
+      } else {
+        warn!(
+          "unable to open pidfd for worker process: {}, falling back to blocking wait()",
+          err
+        );
+      }
+      return None;
+    }
+    Some(fd as RawFd)
+  }
+
+  #[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "x86",
+    target_arch = "arm"
+  )))]
+  pub fn try_open_pidfd(_pid: u32) -> Option<RawFd> {
+    None
+  }
+
+  pub fn spawn_pidfd_reaper(mut child: Child, pidfd: RawFd, exit_notify: Sender<i32>) {
+    std::thread::Builder::new()
+      .name("worker-status-monitor".to_string())
+      .spawn(move || {
+        let mut poll_fd = libc::pollfd {
+          fd: pidfd,
+          events: libc::POLLIN,
+          revents: 0,
+        };
+
+        // Blocks until the pidfd becomes readable, which happens exactly
+        // when the worker process exits. The actual reaping (and exit code
+        // retrieval) is left to `Child::wait()`, which returns immediately
+        // once the process has already exited.
+        let poll_result = unsafe { libc::poll(&mut poll_fd, 1, -1) };
+        unsafe { libc::close(pidfd) };
+
+        if poll_result < 0 {
+          warn!(
+            "poll on worker pidfd failed: {}, falling back to blocking wait()",
+            std::io::Error::last_os_error()
+          );
+        }
+
+        let result = child.wait();
+        forward_exit_code(result, &exit_notify);
+      })
+      .expect("Unable to spawn worker monitor thread");
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use super::*;
+
+    #[test]
+    fn returns_none_for_a_pid_that_cannot_be_opened() {
+      // Pid 0 is reserved and never assigned to a real process, so this
+      // fails the same way on every kernel: `pidfd_open`/the raw syscall
+      // returns EINVAL on kernels that support it, and ENOSYS on kernels
+      // that don't, both of which fall through to `None` here.
+      assert!(try_open_pidfd(0).is_none());
+    }
+  }
+}